@@ -1,4 +1,8 @@
+use nannou::event::WindowEvent::*;
 use nannou::prelude::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
 
 // DEFAULT PARAMS
 // TURNFACTOR: Controls how much boids can turn in response to their neighbors.
@@ -34,6 +38,21 @@ const BIAS_INCREMENT: f32 = 0.00004;
 // DEFAULT_BIAS_VAL: Sets the initial bias value for boids.
 const DEFAULT_BIAS_VAL: f32 = 0.001;
 
+// MARGIN: Distance from each edge of the window at which boids start turning back inward.
+const MARGIN: f32 = 100.0;
+
+// PREDATOR_RANGE: Distance within which boids notice a predator and flee from it.
+const PREDATOR_RANGE: f32 = 80.0;
+
+// PREDATORTURNFACTOR: Controls how sharply boids turn away from a nearby predator.
+const PREDATORTURNFACTOR: f32 = 0.5;
+
+// BOID_COUNT: Default flock size; override at runtime with the BOID_COUNT env var.
+const BOID_COUNT: usize = 300;
+
+// SEEK_FACTOR: Controls how strongly boids are pulled toward the mouse attractor.
+const SEEK_FACTOR: f32 = 0.3;
+
 struct Params {
     turnfactor: f32,
     visual_range: f32,
@@ -46,57 +65,229 @@ struct Params {
     max_bias: f32,
     bias_increment: f32,
     default_bias_val: f32,
+    predator_range: f32,
+    predatorturnfactor: f32,
+    seek_factor: f32,
+}
+
+enum ScoutGroup {
+    None,
+    Group1,
+    Group2,
 }
 
 struct Boid {
     position: Vec2,
     velocity: Vec2,
-    direction_x: DirectionX,
-    direction_y: DirectionY,
+    scout_group: ScoutGroup,
+    biasval: f32,
 }
 
 impl Boid {
-    fn separate(
-        &mut self,
-        boid_positions: &Vec<Vec2>,
-        current_boid_index: usize,
-        avoid_factor: f32,
-        protected_range: f32,
-        max_speed: f32,
-    ) {
-        let mut close_dx = 0.0f32;
-        let mut close_dy = 0.0f32;
-        for i in 0..boid_positions.len() {
-            if i == current_boid_index {
-                continue;
+    /// Adapts `biasval` toward `max_bias` while this boid is already heading in its scout
+    /// group's preferred x direction, then blends that bias into the velocity so untagged
+    /// boids drift along with the scouts instead of needing explicit leadership links.
+    fn apply_bias(&mut self, max_bias: f32, bias_increment: f32) {
+        let bias_direction = match self.scout_group {
+            ScoutGroup::None => return,
+            ScoutGroup::Group1 => 1.0,
+            ScoutGroup::Group2 => -1.0,
+        };
+
+        if self.velocity[0] * bias_direction > 0.0 {
+            self.biasval = (self.biasval + bias_increment).min(max_bias);
+        } else {
+            self.biasval = (self.biasval - bias_increment).max(bias_increment);
+        }
+
+        self.velocity[0] = (1.0 - self.biasval) * self.velocity[0] + self.biasval * bias_direction;
+    }
+}
+
+enum BoundaryMode {
+    Wrap,
+    Bounce,
+}
+
+/// Uniform spatial hash keyed by `visual_range`-sized cells, so each boid only has to scan
+/// its own cell and the eight surrounding ones instead of the whole flock. In `Wrap` mode,
+/// `wrap_cells` (grid columns/rows) is set so cell lookups wrap around the torus seam too.
+struct Grid {
+    cell_size: f32,
+    wrap_cells: Option<(i32, i32)>,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl Grid {
+    fn build(positions: &[Vec2], cell_size: f32, wrap_cells: Option<(i32, i32)>) -> Self {
+        let mut cells: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for (i, position) in positions.iter().enumerate() {
+            cells
+                .entry(Self::cell_of(*position, cell_size, wrap_cells))
+                .or_insert_with(Vec::new)
+                .push(i);
+        }
+        Grid {
+            cell_size,
+            wrap_cells,
+            cells,
+        }
+    }
+
+    fn cell_of(position: Vec2, cell_size: f32, wrap_cells: Option<(i32, i32)>) -> (i32, i32) {
+        let mut cell_x = (position.x / cell_size).floor() as i32;
+        let mut cell_y = (position.y / cell_size).floor() as i32;
+        if let Some((cols, rows)) = wrap_cells {
+            cell_x = cell_x.rem_euclid(cols);
+            cell_y = cell_y.rem_euclid(rows);
+        }
+        (cell_x, cell_y)
+    }
+
+    fn neighbor_candidates(&self, position: Vec2) -> Vec<usize> {
+        let (cell_x, cell_y) = Self::cell_of(position, self.cell_size, self.wrap_cells);
+        let mut candidates = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let key = match self.wrap_cells {
+                    Some((cols, rows)) => ((cell_x + dx).rem_euclid(cols), (cell_y + dy).rem_euclid(rows)),
+                    None => (cell_x + dx, cell_y + dy),
+                };
+                if let Some(indices) = self.cells.get(&key) {
+                    candidates.extend_from_slice(indices);
+                }
+            }
+        }
+        candidates
+    }
+}
+
+/// Combined separation/alignment/cohesion velocity delta for one boid, scanning only the
+/// neighbor candidates the grid hands in rather than the whole flock. When `wrap_size` is
+/// set (torus `Wrap` boundary mode), each neighbor's offset is taken as the shortest of the
+/// direct delta and the delta across the wrapped seam, so flocks stay coherent across it.
+fn flocking_delta(
+    current_index: usize,
+    position: Vec2,
+    velocity: Vec2,
+    neighbor_candidates: &[usize],
+    boid_positions: &[Vec2],
+    boid_velocities: &[Vec2],
+    params: &Params,
+    wrap_size: Option<Vec2>,
+) -> Vec2 {
+    let mut close_dx = 0.0f32;
+    let mut close_dy = 0.0f32;
+    let mut vx_avg = 0.0f32;
+    let mut vy_avg = 0.0f32;
+    let mut xpos_avg = 0.0f32;
+    let mut ypos_avg = 0.0f32;
+    let mut neighbor_count = 0u32;
+
+    for &i in neighbor_candidates {
+        if i == current_index {
+            continue;
+        }
+        let other_position = boid_positions[i];
+        let mut dx = position.x - other_position.x;
+        let mut dy = position.y - other_position.y;
+        if let Some(wrap_size) = wrap_size {
+            if dx.abs() > wrap_size.x / 2.0 {
+                dx -= wrap_size.x * dx.signum();
             }
-            let other_boid_position = boid_positions[i];
-            let distance = self.position - other_boid_position;
-            if distance[0].abs() < protected_range || distance[1].abs() < protected_range {
-                close_dx += self.position[0] - other_boid_position[0];
-                close_dy += self.position[1] - other_boid_position[1];
+            if dy.abs() > wrap_size.y / 2.0 {
+                dy -= wrap_size.y * dy.signum();
             }
         }
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        if distance < params.protected_range {
+            close_dx += dx;
+            close_dy += dy;
+        } else if distance < params.visual_range {
+            vx_avg += boid_velocities[i][0];
+            vy_avg += boid_velocities[i][1];
+            // Effective (wrap-adjusted) position of the neighbor, as seen from `position`.
+            xpos_avg += position.x - dx;
+            ypos_avg += position.y - dy;
+            neighbor_count += 1;
+        }
+    }
+
+    let mut delta = vec2(close_dx * params.avoid_factor, close_dy * params.avoid_factor);
+
+    if neighbor_count > 0 {
+        let neighbor_count = neighbor_count as f32;
+        delta.x += (vx_avg / neighbor_count - velocity.x) * params.matching_factor;
+        delta.y += (vy_avg / neighbor_count - velocity.y) * params.matching_factor;
+        delta.x += (xpos_avg / neighbor_count - position.x) * params.centering_factor;
+        delta.y += (ypos_avg / neighbor_count - position.y) * params.centering_factor;
+    }
+
+    delta
+}
+
+/// Velocity delta steering away from nearby predators, plus whether any were close enough to
+/// flee from at all, so the caller can skip normal flocking that frame.
+fn flee_delta(position: Vec2, predator_positions: &[Vec2], params: &Params) -> (bool, Vec2) {
+    let mut away_dx = 0.0f32;
+    let mut away_dy = 0.0f32;
+    let mut predators_in_range = 0u32;
+
+    for predator_position in predator_positions {
+        let distance = position - *predator_position;
+        if distance.length() < params.predator_range {
+            away_dx += distance[0];
+            away_dy += distance[1];
+            predators_in_range += 1;
+        }
+    }
 
-        self.position[0] += close_dx * avoid_factor;
-        self.position[1] += close_dy * avoid_factor;
+    if predators_in_range > 0 {
+        let predators_in_range = predators_in_range as f32;
+        (
+            true,
+            vec2(
+                away_dx.signum() * params.predatorturnfactor * predators_in_range,
+                away_dy.signum() * params.predatorturnfactor * predators_in_range,
+            ),
+        )
+    } else {
+        (false, Vec2::ZERO)
     }
 }
 
-enum DirectionX {
-    Right,
-    Left,
+struct Predator {
+    position: Vec2,
+    velocity: Vec2,
 }
 
-enum DirectionY {
-    Top,
-    Bottom,
+impl Predator {
+    /// Chases the nearest boid by steering velocity toward it.
+    fn chase(&mut self, boid_positions: &Vec<Vec2>, turnfactor: f32) {
+        let nearest = boid_positions.iter().min_by(|a, b| {
+            let dist_a = (self.position - **a).length();
+            let dist_b = (self.position - **b).length();
+            dist_a.partial_cmp(&dist_b).unwrap()
+        });
+
+        if let Some(target) = nearest {
+            let towards = *target - self.position;
+            self.velocity[0] += towards[0].signum() * turnfactor;
+            self.velocity[1] += towards[1].signum() * turnfactor;
+        }
+    }
 }
 
 struct Model {
     _boids: Vec<Boid>,
+    _predators: Vec<Predator>,
     _window: WindowId,
     _params: Params,
+    _frame_time: Duration,
+    _show_visual_range: bool,
+    _attractor: Option<Vec2>,
+    _boundary_mode: BoundaryMode,
 }
 
 fn model(_app: &App) -> Model {
@@ -116,78 +307,227 @@ fn model(_app: &App) -> Model {
         max_speed: MAX_SPEED,
         min_speed: MIN_SPEED,
         max_bias: MAX_BIAS,
+        predator_range: PREDATOR_RANGE,
+        predatorturnfactor: PREDATORTURNFACTOR,
+        seek_factor: SEEK_FACTOR,
     };
 
+    let boid_count: usize = std::env::var("BOID_COUNT")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(BOID_COUNT);
+
     let mut boids = Vec::new();
 
-    for i in 0..10 {
+    for i in 0..boid_count {
+        let scout_group = if i % 10 == 0 {
+            ScoutGroup::Group1
+        } else if i % 10 == 5 {
+            ScoutGroup::Group2
+        } else {
+            ScoutGroup::None
+        };
+
         let boid = Boid {
             position: pt2(position[0] + i as f32 * 10.0, position[1] + i as f32 * 30.0),
             velocity,
-            direction_x: DirectionX::Right,
-            direction_y: DirectionY::Top,
+            scout_group,
+            biasval: DEFAULT_BIAS_VAL,
         };
 
         boids.push(boid);
     }
 
+    let predators = vec![Predator {
+        position: pt2(0.0, 0.0),
+        velocity: pt2(-3.0, 2.0),
+    }];
+
     Model {
         _boids: boids,
+        _predators: predators,
         _window: window,
         _params: params,
+        _frame_time: Duration::ZERO,
+        _show_visual_range: false,
+        _attractor: None,
+        _boundary_mode: BoundaryMode::Bounce,
+    }
+}
+
+fn event(_app: &App, _model: &mut Model, event: Event) {
+    let Event::WindowEvent {
+        simple: Some(window_event),
+        ..
+    } = event
+    else {
+        return;
+    };
+
+    match window_event {
+        KeyPressed(Key::V) => _model._show_visual_range = !_model._show_visual_range,
+        KeyPressed(Key::B) => {
+            _model._boundary_mode = match _model._boundary_mode {
+                BoundaryMode::Bounce => BoundaryMode::Wrap,
+                BoundaryMode::Wrap => BoundaryMode::Bounce,
+            }
+        }
+        MousePressed(MouseButton::Left) => {
+            let position = _app.mouse.position();
+            let velocity = vec2(random_range(-3.0, 3.0), random_range(-3.0, 3.0));
+            _model._boids.push(Boid {
+                position,
+                velocity,
+                scout_group: ScoutGroup::None,
+                biasval: _model._params.default_bias_val,
+            });
+        }
+        MousePressed(MouseButton::Right) => {
+            _model._attractor = Some(_app.mouse.position());
+        }
+        MouseReleased(MouseButton::Right) => {
+            _model._attractor = None;
+        }
+        MouseMoved(position) => {
+            if _model._attractor.is_some() {
+                _model._attractor = Some(position);
+            }
+        }
+        _ => {}
     }
 }
 
 fn update(_app: &App, _model: &mut Model, _update: Update) {
+    _model._frame_time = _update.since_last;
+
     let boundary = _app.window_rect();
     let boids = &mut _model._boids;
 
     let mut boid_positions_snapshot: Vec<Vec2> = Vec::new();
+    let mut boid_velocities_snapshot: Vec<Vec2> = Vec::new();
 
     for boid in boids {
         boid_positions_snapshot.push(boid.position);
+        boid_velocities_snapshot.push(boid.velocity);
+    }
+
+    let mut predator_positions_snapshot: Vec<Vec2> = Vec::new();
+    for predator in &_model._predators {
+        predator_positions_snapshot.push(predator.position);
     }
 
+    let wrap_size = match _model._boundary_mode {
+        BoundaryMode::Wrap => Some(vec2(boundary.w(), boundary.h())),
+        BoundaryMode::Bounce => None,
+    };
+    let wrap_cells = wrap_size.map(|size| {
+        (
+            (size.x / _model._params.visual_range).ceil() as i32,
+            (size.y / _model._params.visual_range).ceil() as i32,
+        )
+    });
+
+    let grid = Grid::build(
+        &boid_positions_snapshot,
+        _model._params.visual_range,
+        wrap_cells,
+    );
+
+    // Compute each boid's velocity delta in parallel from the read-only snapshots, then apply
+    // them in a second serial pass below so concurrent updates never alias the same boid.
+    let velocity_deltas: Vec<Vec2> = (0..boid_positions_snapshot.len())
+        .into_par_iter()
+        .map(|i| {
+            let position = boid_positions_snapshot[i];
+            let velocity = boid_velocities_snapshot[i];
+
+            let (fleeing, delta) =
+                flee_delta(position, &predator_positions_snapshot, &_model._params);
+            if fleeing {
+                return delta;
+            }
+
+            let neighbor_candidates = grid.neighbor_candidates(position);
+            flocking_delta(
+                i,
+                position,
+                velocity,
+                &neighbor_candidates,
+                &boid_positions_snapshot,
+                &boid_velocities_snapshot,
+                &_model._params,
+                wrap_size,
+            )
+        })
+        .collect();
+
     let boids = &mut _model._boids;
 
     for i in 0..boids.len() {
         let boid = &mut boids[i];
-        boid.separate(
-            &boid_positions_snapshot,
-            i,
-            _model._params.avoid_factor,
-            _model._params.protected_range,
-            _model._params.max_speed,
-        );
+        boid.velocity += velocity_deltas[i];
 
-        if boid.velocity[0] > _model._params.max_speed {
-            boid.velocity[0] = _model._params.max_speed;
-        }
+        boid.apply_bias(_model._params.max_bias, _model._params.bias_increment);
 
-        if boid.velocity[1] > _model._params.max_speed {
-            boid.velocity[1] = _model._params.max_speed;
+        if let Some(attractor) = _model._attractor {
+            let seek = (attractor - boid.position).normalize_or_zero() * _model._params.seek_factor;
+            boid.velocity += seek;
         }
-        if boid.position[0] >= boundary.right() - 10.0 {
-            boid.direction_x = DirectionX::Left;
-        } else if boid.position[0] <= boundary.left() + 10.0 {
-            boid.direction_x = DirectionX::Right;
+
+        if let BoundaryMode::Bounce = _model._boundary_mode {
+            let left_margin = boundary.left() + MARGIN;
+            let right_margin = boundary.right() - MARGIN;
+            let top_margin = boundary.top() - MARGIN;
+            let bottom_margin = boundary.bottom() + MARGIN;
+
+            if boid.position[0] < left_margin {
+                boid.velocity[0] += _model._params.turnfactor;
+            }
+            if boid.position[0] > right_margin {
+                boid.velocity[0] -= _model._params.turnfactor;
+            }
+            if boid.position[1] > top_margin {
+                boid.velocity[1] -= _model._params.turnfactor;
+            }
+            if boid.position[1] < bottom_margin {
+                boid.velocity[1] += _model._params.turnfactor;
+            }
         }
 
-        if boid.position[1] >= boundary.top() - 10.0 {
-            boid.direction_y = DirectionY::Bottom;
-        } else if boid.position[1] <= boundary.bottom() + 10.0 {
-            boid.direction_y = DirectionY::Top;
+        let speed = boid.velocity.length();
+        if speed > _model._params.max_speed {
+            boid.velocity = boid.velocity / speed * _model._params.max_speed;
+        } else if speed < _model._params.min_speed && speed > 0.0 {
+            boid.velocity = boid.velocity / speed * _model._params.min_speed;
         }
 
-        match boid.direction_x {
-            DirectionX::Left => boid.position[0] -= boid.velocity[0],
-            DirectionX::Right => boid.position[0] += boid.velocity[0],
+        boid.position += boid.velocity;
+
+        if let BoundaryMode::Wrap = _model._boundary_mode {
+            if boid.position.x < boundary.left() {
+                boid.position.x += boundary.w();
+            } else if boid.position.x > boundary.right() {
+                boid.position.x -= boundary.w();
+            }
+            if boid.position.y < boundary.bottom() {
+                boid.position.y += boundary.h();
+            } else if boid.position.y > boundary.top() {
+                boid.position.y -= boundary.h();
+            }
         }
+    }
+
+    for predator in &mut _model._predators {
+        predator.chase(&boid_positions_snapshot, _model._params.turnfactor);
 
-        match boid.direction_y {
-            DirectionY::Top => boid.position[1] += boid.velocity[1],
-            DirectionY::Bottom => boid.position[1] -= boid.velocity[1],
+        let speed = predator.velocity.length();
+        if speed > _model._params.max_speed {
+            predator.velocity = predator.velocity / speed * _model._params.max_speed;
+        } else if speed < _model._params.min_speed && speed > 0.0 {
+            predator.velocity = predator.velocity / speed * _model._params.min_speed;
         }
+
+        predator.position += predator.velocity;
     }
 }
 
@@ -196,13 +536,54 @@ fn view(_app: &App, _model: &Model, frame: Frame) {
     draw.background().color(WHITE);
 
     for boid in &_model._boids {
-        let position = boid.position;
-        draw.ellipse().xy(position).w_h(20.0, 20.0).color(BLACK);
+        if _model._show_visual_range {
+            draw.ellipse()
+                .xy(boid.position)
+                .radius(_model._params.visual_range)
+                .color(rgba(0.2, 0.2, 1.0, 0.05));
+        }
+
+        let speed = boid.velocity.length();
+        let hue = map_range(
+            speed.clamp(_model._params.min_speed, _model._params.max_speed),
+            _model._params.min_speed,
+            _model._params.max_speed,
+            0.6,
+            0.0,
+        );
+
+        draw.tri()
+            .points(pt2(12.0, 0.0), pt2(-8.0, 6.0), pt2(-8.0, -6.0))
+            .xy(boid.position)
+            .rotate(boid.velocity.angle())
+            .color(hsl(hue, 1.0, 0.5));
     }
 
+    for predator in &_model._predators {
+        draw.ellipse()
+            .xy(predator.position)
+            .w_h(30.0, 30.0)
+            .color(RED);
+    }
+
+    let frame_time_ms = _model._frame_time.as_secs_f32() * 1000.0;
+    let fps = if frame_time_ms > 0.0 {
+        1000.0 / frame_time_ms
+    } else {
+        0.0
+    };
+    let boundary = _app.window_rect();
+    draw.text(&format!("{:.0} fps ({:.1} ms/frame)", fps, frame_time_ms))
+        .xy(pt2(boundary.left() + 70.0, boundary.top() - 20.0))
+        .color(BLACK);
+
     draw.to_frame(_app, &frame).unwrap();
 }
 
 fn main() {
-    nannou::app(model).update(update).view(view).run();
+    nannou::app(model)
+        .event(event)
+        .update(update)
+        .view(view)
+        .run();
 }